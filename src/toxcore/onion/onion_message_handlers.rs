@@ -0,0 +1,115 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Registry that dispatches an incoming `OnionMessagePayload` to the handler
+registered for its `kind`, so applications can add new message kinds without
+touching the onion transport itself.
+*/
+
+use std::collections::HashMap;
+
+use toxcore::crypto_core::*;
+use toxcore::onion::packet::*;
+
+/// A handler for one `OnionMessagePayload` kind.
+pub type OnionMessageHandler = Box<dyn Fn(&PublicKey, &[u8]) + Send>;
+
+/** Keeps track of which handler should receive an `OnionMessagePayload` of a
+given `kind` once it reaches the final onion hop.
+*/
+#[derive(Default)]
+pub struct OnionMessageHandlers {
+    handlers: HashMap<u8, OnionMessageHandler>
+}
+
+impl OnionMessageHandlers {
+    /// Create a new, empty `OnionMessageHandlers`.
+    pub fn new() -> OnionMessageHandlers {
+        OnionMessageHandlers {
+            handlers: HashMap::new()
+        }
+    }
+
+    /** Register `handler` to be called for every `OnionMessagePayload` whose
+    `kind` equals `kind`. Registering a new handler for a `kind` that already
+    has one replaces it.
+    */
+    pub fn register<F>(&mut self, kind: u8, handler: F)
+    where
+        F: Fn(&PublicKey, &[u8]) + Send + 'static
+    {
+        self.handlers.insert(kind, Box::new(handler));
+    }
+
+    /** Dispatch `payload`, received from `sender_pk`, to the handler
+    registered for its `kind`.
+
+    Returns `true` if a handler was found and called, `false` if `payload`'s
+    `kind` has no registered handler.
+    */
+    pub fn handle(&self, sender_pk: &PublicKey, payload: &OnionMessagePayload) -> bool {
+        match self.handlers.get(&payload.kind) {
+            Some(handler) => {
+                handler(sender_pk, &payload.inner);
+                true
+            },
+            None => false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn handle_calls_registered_handler() {
+        let received = Arc::new(Mutex::new(None));
+        let received_c = received.clone();
+
+        let mut handlers = OnionMessageHandlers::new();
+        handlers.register(7, move |_sender_pk, inner| {
+            *received_c.lock().unwrap() = Some(inner.to_vec());
+        });
+
+        let (sender_pk, _sender_sk) = gen_keypair();
+        let payload = OnionMessagePayload {
+            kind: 7,
+            inner: vec![42, 123]
+        };
+
+        assert!(handlers.handle(&sender_pk, &payload));
+        assert_eq!(*received.lock().unwrap(), Some(vec![42, 123]));
+    }
+
+    #[test]
+    fn handle_returns_false_for_unknown_kind() {
+        let handlers = OnionMessageHandlers::new();
+        let (sender_pk, _sender_sk) = gen_keypair();
+        let payload = OnionMessagePayload {
+            kind: 7,
+            inner: vec![42, 123]
+        };
+
+        assert!(!handlers.handle(&sender_pk, &payload));
+    }
+}
@@ -0,0 +1,233 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Builder that nests the payloads of `OnionRequest0`/`OnionRequest1`/
+`OnionRequest2` over a full onion path in a single call.
+*/
+
+use std::io::{Error, ErrorKind};
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+use toxcore::onion::packet::*;
+use toxcore::onion::packet::onion_padding;
+
+/** A path through the onion network: the ordered relays a packet travels
+through before it reaches its destination. The last entry of `nodes` is the
+destination node itself.
+
+Building each layer by hand (generating a per-hop shared secret, sealing that
+hop's payload, then sealing the next one around it) is repetitive and easy to
+get wrong, so [`OnionPath::new`](./struct.OnionPath.html#method.new) does it
+in one call and hands back the nested `OnionRequest0` ready to send to
+`nodes[0]`, together with the `PrecomputedKey` of every hop.
+
+`OnionPath::new` never produces an `OnionReturn` for `nodes[1]` or `nodes[2]`
+- every relay adds its own when it forwards a packet on, sealed with a
+symmetric key only that relay knows, so a sender has no way to build one
+itself. `inner` at every layer but the innermost is therefore exactly the
+ciphertext the next hop's `OnionRequest1`/`OnionRequest2` will carry as its
+`payload`, not a fully framed packet.
+*/
+pub struct OnionPath;
+
+impl OnionPath {
+    /** Build the outgoing onion packet for `inner_payload` routed over
+    `nodes`, using `session_sk` as the per-path ephemeral secret key whose
+    public counterpart is `session_pk`.
+
+    `nodes` must contain exactly 3 entries: the entry node, the middle node
+    and the node that will handle `inner_payload` itself (e.g. an onion
+    announce or onion data request already serialized by the caller).
+
+    Returns the nested `OnionRequest0` ready to be sent to `nodes[0]` along
+    with the `PrecomputedKey` of every hop in path order, so that an
+    `OnionError` that comes back can later be tried against
+    [`EncryptedOnionError::get_payload_with_any`](../packet/struct.EncryptedOnionError.html#method.get_payload_with_any).
+
+    Returns `Error` if `nodes` doesn't contain exactly 3 entries.
+    */
+    pub fn new(nodes: &[(IpPort, PublicKey)], inner_payload: &[u8], session_pk: &PublicKey, session_sk: &SecretKey) -> Result<(OnionRequest0, Vec<PrecomputedKey>), Error> {
+        if nodes.len() != 3 {
+            return Err(Error::new(ErrorKind::InvalidInput,
+                format!("onion path must consist of exactly 3 nodes, got {}", nodes.len())));
+        }
+
+        let shared_secrets: Vec<PrecomputedKey> = nodes.iter()
+            .map(|&(_, ref node_pk)| encrypt_precompute(node_pk, session_sk))
+            .collect();
+
+        // Every layer is sealed under the same nonce. Since each layer uses
+        // a different shared secret this is safe, and it means every relay
+        // can reuse the nonce it already sees in plaintext on the packet it
+        // received when it frames the next packet forward, instead of
+        // needing some other channel to learn it.
+        let nonce = gen_nonce();
+
+        // innermost layer: the payload is handled locally by the last node,
+        // so it carries no further routing information
+        let request_2_payload = OnionRequest2Payload {
+            inner: inner_payload.to_vec()
+        };
+        let sealed_2 = OnionPath::seal(&nonce, &shared_secrets[2], &request_2_payload, onion_padding::ONION_REQUEST_2_PADDED_SIZE);
+
+        // `sealed_2` is exactly the ciphertext node1 will forward untouched
+        // as the `payload` of the `OnionRequest2` it frames with its own
+        // `OnionReturn` - we never build that packet ourselves
+        let request_1_payload = OnionRequest1Payload {
+            ip_port: nodes[2].0.clone(),
+            temporary_pk: *session_pk,
+            inner: sealed_2
+        };
+        let sealed_1 = OnionPath::seal(&nonce, &shared_secrets[1], &request_1_payload, onion_padding::ONION_REQUEST_1_PADDED_SIZE);
+
+        let request_0_payload = OnionRequest0Payload {
+            ip_port: nodes[1].0.clone(),
+            temporary_pk: *session_pk,
+            inner: sealed_1
+        };
+        let sealed_0 = OnionPath::seal(&nonce, &shared_secrets[0], &request_0_payload, onion_padding::ONION_REQUEST_0_PADDED_SIZE);
+
+        let request_0 = OnionRequest0 {
+            nonce,
+            temporary_pk: *session_pk,
+            payload: sealed_0
+        };
+
+        Ok((request_0, shared_secrets))
+    }
+
+    /** Serialize `payload`, pad it to `padded_size` with
+    [`onion_padding::pad`](../packet/onion_padding/fn.pad.html) and seal it
+    with `shared_secret` under `nonce`, so that an observer watching a relay
+    can't correlate packets by the length of their ciphertext.
+    */
+    fn seal<P: ToBytes>(nonce: &Nonce, shared_secret: &PrecomputedKey, payload: &P, padded_size: usize) -> Vec<u8> {
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let padded = onion_padding::pad(&buf[..size], padded_size);
+        seal_precomputed(&padded, nonce, shared_secret)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn unwrap_done<T: ::std::fmt::Debug>(res: IResult<&[u8], T>) -> T {
+        match res {
+            IResult::Done(_, value) => value,
+            other => panic!("expected IResult::Done, got {:?}", other)
+        }
+    }
+
+    #[test]
+    fn onion_path_new_produces_one_shared_secret_per_hop() {
+        let (session_pk, session_sk) = gen_keypair();
+        let nodes: Vec<(IpPort, PublicKey)> = (0..3).map(|i| {
+            (
+                IpPort {
+                    ip_addr: "127.0.0.1".parse().unwrap(),
+                    port: 33445 + i
+                },
+                gen_keypair().0
+            )
+        }).collect();
+
+        let (packet, shared_secrets) = OnionPath::new(&nodes, &[42, 123], &session_pk, &session_sk).unwrap();
+
+        assert_eq!(shared_secrets.len(), 3);
+        assert_eq!(packet.temporary_pk, session_pk);
+    }
+
+    #[test]
+    fn onion_path_new_requires_exactly_three_nodes() {
+        let (session_pk, session_sk) = gen_keypair();
+        let nodes: Vec<(IpPort, PublicKey)> = (0..2).map(|i| {
+            (
+                IpPort {
+                    ip_addr: "127.0.0.1".parse().unwrap(),
+                    port: 33445 + i
+                },
+                gen_keypair().0
+            )
+        }).collect();
+
+        assert!(OnionPath::new(&nodes, &[42, 123], &session_pk, &session_sk).is_err());
+    }
+
+    #[test]
+    fn onion_path_new_can_be_unwrapped_hop_by_hop() {
+        let (session_pk, session_sk) = gen_keypair();
+        let node_keys: Vec<_> = (0..3).map(|_| gen_keypair()).collect();
+        let nodes: Vec<(IpPort, PublicKey)> = node_keys.iter().enumerate().map(|(i, &(pk, _))| {
+            (
+                IpPort {
+                    ip_addr: "127.0.0.1".parse().unwrap(),
+                    port: 33445 + i as u16
+                },
+                pk
+            )
+        }).collect();
+
+        let inner_payload = vec![42, 123];
+        let (request_0, shared_secrets) = OnionPath::new(&nodes, &inner_payload, &session_pk, &session_sk).unwrap();
+
+        // node0 peels its own layer off of the packet it received; `inner`
+        // is the ciphertext it forwards to node1 unchanged, reusing the
+        // nonce it can already see on `request_0` itself
+        let request_0_payload = request_0.get_padded_payload(&shared_secrets[0]).unwrap();
+        let decrypted_1 = open_precomputed(&request_0_payload.inner, &request_0.nonce, &shared_secrets[1]).unwrap();
+        let decrypted_1 = onion_padding::unpad(decrypted_1, onion_padding::ONION_REQUEST_1_PADDED_SIZE).unwrap();
+        let request_1_payload = unwrap_done(OnionRequest1Payload::from_bytes(&decrypted_1));
+
+        // node1 peels its own layer off of what node0 forwarded
+        let decrypted_2 = open_precomputed(&request_1_payload.inner, &request_0.nonce, &shared_secrets[2]).unwrap();
+        let decrypted_2 = onion_padding::unpad(decrypted_2, onion_padding::ONION_REQUEST_2_PADDED_SIZE).unwrap();
+        let request_2_payload = unwrap_done(OnionRequest2Payload::from_bytes(&decrypted_2));
+
+        // node2 peels its own layer and recovers the original inner payload
+        assert_eq!(request_2_payload.inner, inner_payload);
+    }
+
+    #[test]
+    fn onion_path_new_produces_constant_length_packets_regardless_of_payload_size() {
+        let (session_pk, session_sk) = gen_keypair();
+        let nodes: Vec<(IpPort, PublicKey)> = (0..3).map(|i| {
+            (
+                IpPort {
+                    ip_addr: "127.0.0.1".parse().unwrap(),
+                    port: 33445 + i
+                },
+                gen_keypair().0
+            )
+        }).collect();
+
+        let (short_packet, _) = OnionPath::new(&nodes, &[42; 8], &session_pk, &session_sk).unwrap();
+        let (long_packet, _) = OnionPath::new(&nodes, &[42; 400], &session_pk, &session_sk).unwrap();
+
+        let mut short_buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, short_size) = short_packet.to_bytes((&mut short_buf, 0)).unwrap();
+        let mut long_buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, long_size) = long_packet.to_bytes((&mut long_buf, 0)).unwrap();
+
+        assert_eq!(short_size, long_size);
+    }
+}
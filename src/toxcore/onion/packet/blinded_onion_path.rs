@@ -0,0 +1,422 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! `BlindedOnionPath` - a pre-built sequence of relays leading to a node that
+publishes it, so a sender can route to the node without learning the real
+public keys of the intermediate relays or the node's position in the chain.
+*/
+
+use super::*;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+
+use nom::be_u8;
+use std::io::{Error, ErrorKind};
+
+/// A single hop of a `BlindedOnionPath`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlindedOnionPathHop {
+    /// Opaque pseudonym for this hop's real `PublicKey` - derived with a
+    /// one-way hash, so it can't be turned back into the real key or reused
+    /// for Diffie-Hellman the way a curve point could.
+    pub blinded_pk: PublicKey,
+    /// Ephemeral `PublicKey` this hop's shared secret is derived from, i.e.
+    /// `encrypt_precompute(ephemeral_pk, node_sk)`. Unlike `blinded_pk` this
+    /// one is a real curve point - ephemeral keys are meant to be public, it
+    /// is `node_sk` that keeps the Diffie-Hellman secure.
+    pub ephemeral_pk: PublicKey,
+    /// Nonce the destination used to seal `encrypted_next_hop`
+    pub nonce: Nonce,
+    /// Sealed [`BlindedOnionPathNextHop`](./struct.BlindedOnionPathNextHop.html),
+    /// decryptable only by this hop with the shared secret it derives from
+    /// `ephemeral_pk` and its own real secret key. Empty for the last hop,
+    /// which is the destination itself.
+    pub encrypted_next_hop: Vec<u8>
+}
+
+impl FromBytes for BlindedOnionPathHop {
+    named!(from_bytes<BlindedOnionPathHop>, do_parse!(
+        blinded_pk: call!(PublicKey::from_bytes) >>
+        ephemeral_pk: call!(PublicKey::from_bytes) >>
+        nonce: call!(Nonce::from_bytes) >>
+        len: be_u8 >>
+        encrypted_next_hop: take!(len) >>
+        (BlindedOnionPathHop {
+            blinded_pk,
+            ephemeral_pk,
+            nonce,
+            encrypted_next_hop: encrypted_next_hop.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for BlindedOnionPathHop {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.blinded_pk.as_ref()) >>
+            gen_slice!(self.ephemeral_pk.as_ref()) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_be_u8!(self.encrypted_next_hop.len() as u8) >>
+            gen_slice!(self.encrypted_next_hop)
+        )
+    }
+}
+
+/** Unencrypted payload of a `BlindedOnionPathHop`'s `encrypted_next_hop`,
+telling the hop where to forward and which ephemeral `PublicKey` the next hop
+expects to derive its own shared secret from - the same `temporary_pk`
+forwarding convention [`OnionRequest0Payload`](./struct.OnionRequest0Payload.html)
+and [`OnionRequest1Payload`](./struct.OnionRequest1Payload.html) use.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`19`     | `IpPort` of the next hop
+`32`     | Next hop's ephemeral `PublicKey`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlindedOnionPathNextHop {
+    /// Address of the next hop
+    pub ip_port: IpPort,
+    /// Ephemeral `PublicKey` the next hop's shared secret is derived from
+    pub ephemeral_pk: PublicKey
+}
+
+impl FromBytes for BlindedOnionPathNextHop {
+    named!(from_bytes<BlindedOnionPathNextHop>, do_parse!(
+        ip_port: call!(IpPort::from_bytes) >>
+        ephemeral_pk: call!(PublicKey::from_bytes) >>
+        (BlindedOnionPathNextHop { ip_port, ephemeral_pk })
+    ));
+}
+
+impl ToBytes for BlindedOnionPathNextHop {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, ip_port| IpPort::to_bytes(ip_port, buf), &self.ip_port) >>
+            gen_slice!(self.ephemeral_pk.as_ref())
+        )
+    }
+}
+
+/** A blinded path to a destination node, spliced by a sender onto the end of
+its own forward onion path.
+
+The destination generates a fresh ephemeral key pair for every hop and, for
+each one, computes a shared secret with that hop's real `PublicKey` via the
+usual `encrypt_precompute`. That shared secret seals a
+[`BlindedOnionPathNextHop`](./struct.BlindedOnionPathNextHop.html) telling the
+hop where to forward and which ephemeral key the *next* hop expects, and also
+derives `blinded_pk`, an opaque pseudonym that lets a hop recognise its own
+entry without revealing its real key to the sender. Only the destination ever
+learns the mapping from blinded ids back to real node public keys.
+
+The usual construction for this kind of path chains a single blinding factor
+from one hop to the next (`blinding_factor[i] = hash(blinding_factor[i-1] ||
+shared_secret[i])`, folded into each hop's key via scalarmult) so that knowing
+one hop's blinded key never helps compute another's. That chaining step is
+scalarmult against an arbitrary hash output, which this crate's
+`crypto_core` has no safe primitive for - see
+[`blinding_factor`](fn.blinding_factor.html) for how this stays unlinkable
+without it.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | Number of hops
+variable | `BlindedOnionPathHop` for each hop
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BlindedOnionPath {
+    /// Blinded relay chain, ending at the destination node itself
+    pub hops: Vec<BlindedOnionPathHop>
+}
+
+impl FromBytes for BlindedOnionPath {
+    named!(from_bytes<BlindedOnionPath>, do_parse!(
+        hops_number: be_u8 >>
+        hops: count!(BlindedOnionPathHop::from_bytes, hops_number as usize) >>
+        (BlindedOnionPath { hops })
+    ));
+}
+
+impl ToBytes for BlindedOnionPath {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(self.hops.len() as u8) >>
+            gen_many_ref!(&self.hops, |buf, hop| BlindedOnionPathHop::to_bytes(hop, buf))
+        )
+    }
+}
+
+/** `blinding_factor = hash(ephemeral_pk || shared_secret)` - a value tying a
+hop's pseudonym to the specific ephemeral key and shared secret the
+destination generated for that hop, and nothing else.
+
+A chained construction would fold the *previous* hop's blinding factor in
+here too, via scalarmult, so that every hop's factor depends on every hop
+before it. This crate's `crypto_core` exposes no scalarmult over an arbitrary
+hash output, only `encrypt_precompute`'s Diffie-Hellman between two real
+curve keys, so there's no sound way to build that chain here. Instead
+[`BlindedOnionPath::new`](struct.BlindedOnionPath.html#method.new) generates
+an *independent* fresh ephemeral key pair per hop rather than deriving one
+from the last: `blinding_factor` is already per-hop-unique without chaining,
+since no two hops ever share an `ephemeral_pk`, and knowing one hop's factor
+gives no information about any other hop's, which is the property the chain
+was for in the first place.
+*/
+fn blinding_factor(ephemeral_pk: &PublicKey, shared_secret: &PrecomputedKey) -> sha512::Digest {
+    let mut data = Vec::with_capacity(PUBLICKEYBYTES * 2);
+    data.extend_from_slice(ephemeral_pk.as_ref());
+    data.extend_from_slice(shared_secret.as_ref());
+    sha512::hash(&data)
+}
+
+/** Derive a hop's opaque `blinded_pk` pseudonym from its real `PublicKey` and
+its [`blinding_factor`](fn.blinding_factor.html). This is a one-way hash, not
+a curve point - it can't be used for Diffie-Hellman and can't be turned back
+into `node_pk` or `blinding_factor`, it only lets a hop recognise which
+published entry is its own.
+*/
+fn blinded_pseudonym(node_pk: &PublicKey, ephemeral_pk: &PublicKey, shared_secret: &PrecomputedKey) -> PublicKey {
+    let factor = blinding_factor(ephemeral_pk, shared_secret);
+    let mut data = Vec::with_capacity(PUBLICKEYBYTES + factor.0.len());
+    data.extend_from_slice(node_pk.as_ref());
+    data.extend_from_slice(factor.as_ref());
+    let digest = sha512::hash(&data);
+    PublicKey::from_slice(&digest.0[..PUBLICKEYBYTES]).expect("hash is long enough for a PublicKey")
+}
+
+impl BlindedOnionPath {
+    /** Build a `BlindedOnionPath` that routes through `nodes`, ordered from
+    the first hop a sender's own path should splice onto, to the last hop -
+    the destination itself.
+
+    Every hop gets its own freshly generated ephemeral key pair; the
+    destination is the only party that ever holds all of them, so it's the
+    only one that can later recompute a hop's shared secret or recognise its
+    `blinded_pk`.
+    */
+    pub fn new(nodes: &[(IpPort, PublicKey)]) -> BlindedOnionPath {
+        let ephemeral_keys: Vec<(PublicKey, SecretKey)> = nodes.iter().map(|_| gen_keypair()).collect();
+
+        let hops = nodes.iter().enumerate().map(|(i, &(_, ref node_pk))| {
+            let (ephemeral_pk, ref ephemeral_sk) = ephemeral_keys[i];
+            let shared_secret = encrypt_precompute(node_pk, ephemeral_sk);
+            let blinded_pk = blinded_pseudonym(node_pk, &ephemeral_pk, &shared_secret);
+
+            let nonce = gen_nonce();
+            let encrypted_next_hop = if i + 1 < nodes.len() {
+                let next_hop = BlindedOnionPathNextHop {
+                    ip_port: nodes[i + 1].0.clone(),
+                    ephemeral_pk: ephemeral_keys[i + 1].0
+                };
+                let mut buf = [0; ONION_MAX_PACKET_SIZE];
+                let (_, size) = next_hop.to_bytes((&mut buf, 0)).unwrap();
+                seal_precomputed(&buf[..size], &nonce, &shared_secret)
+            } else {
+                // last hop is the destination itself - nothing further to forward to
+                Vec::new()
+            };
+
+            BlindedOnionPathHop { blinded_pk, ephemeral_pk, nonce, encrypted_next_hop }
+        }).collect();
+
+        BlindedOnionPath { hops }
+    }
+
+    /** Called by a relay that received a `BlindedOnionPathHop` addressed to
+    it. Decrypts `encrypted_next_hop` with `node_sk` and returns the next
+    hop's address and ephemeral `PublicKey`, or `None` if this hop is the
+    destination.
+    */
+    pub fn open_hop(hop: &BlindedOnionPathHop, node_sk: &SecretKey) -> Result<Option<(IpPort, PublicKey)>, Error> {
+        if hop.encrypted_next_hop.is_empty() {
+            return Ok(None);
+        }
+
+        let shared_secret = encrypt_precompute(&hop.ephemeral_pk, node_sk);
+        let decrypted = open_precomputed(&hop.encrypted_next_hop, &hop.nonce, &shared_secret)
+            .map_err(|e| {
+                debug!("Decrypting BlindedOnionPathHop failed!");
+                Error::new(ErrorKind::Other,
+                    format!("BlindedOnionPathHop decrypt error: {:?}", e))
+            })?;
+        match BlindedOnionPathNextHop::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                error!(target: "Onion", "BlindedOnionPathNextHop deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("BlindedOnionPathNextHop deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                error!(target: "Onion", "BlindedOnionPathNextHop deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("BlindedOnionPathNextHop deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, next_hop) => {
+                Ok(Some((next_hop.ip_port, next_hop.ephemeral_pk)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        blinded_onion_path_hop_encode_decode,
+        BlindedOnionPathHop {
+            blinded_pk: gen_keypair().0,
+            ephemeral_pk: gen_keypair().0,
+            nonce: gen_nonce(),
+            encrypted_next_hop: vec![42, 123]
+        }
+    );
+
+    encode_decode_test!(
+        blinded_onion_path_next_hop_encode_decode,
+        BlindedOnionPathNextHop {
+            ip_port: IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            ephemeral_pk: gen_keypair().0
+        }
+    );
+
+    encode_decode_test!(
+        blinded_onion_path_encode_decode,
+        BlindedOnionPath {
+            hops: vec![
+                BlindedOnionPathHop {
+                    blinded_pk: gen_keypair().0,
+                    ephemeral_pk: gen_keypair().0,
+                    nonce: gen_nonce(),
+                    encrypted_next_hop: vec![42, 123]
+                },
+                BlindedOnionPathHop {
+                    blinded_pk: gen_keypair().0,
+                    ephemeral_pk: gen_keypair().0,
+                    nonce: gen_nonce(),
+                    encrypted_next_hop: Vec::new()
+                }
+            ]
+        }
+    );
+
+    fn make_nodes(n: u16) -> Vec<(IpPort, PublicKey, SecretKey)> {
+        (0..n).map(|i| {
+            let (pk, sk) = gen_keypair();
+            (
+                IpPort {
+                    ip_addr: "127.0.0.1".parse().unwrap(),
+                    port: 33445 + i
+                },
+                pk,
+                sk
+            )
+        }).collect()
+    }
+
+    #[test]
+    fn blinded_onion_path_hides_real_node_keys() {
+        let nodes_with_keys = make_nodes(3);
+        let nodes: Vec<(IpPort, PublicKey)> = nodes_with_keys.iter()
+            .map(|&(ref ip_port, pk, _)| (ip_port.clone(), pk))
+            .collect();
+
+        let path = BlindedOnionPath::new(&nodes);
+
+        assert_eq!(path.hops.len(), nodes.len());
+        // none of the published blinded ids or ephemeral keys equal the real node keys
+        for (hop, &(_, ref node_pk)) in path.hops.iter().zip(nodes.iter()) {
+            assert_ne!(&hop.blinded_pk, node_pk);
+            assert_ne!(&hop.ephemeral_pk, node_pk);
+        }
+        // the last hop (the destination) has nothing further to forward to
+        assert!(path.hops.last().unwrap().encrypted_next_hop.is_empty());
+    }
+
+    #[test]
+    fn blinded_pseudonym_is_deterministic_but_differs_per_hop() {
+        let (node_pk, _node_sk) = gen_keypair();
+        let (ephemeral_pk, ephemeral_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&node_pk, &ephemeral_sk);
+
+        // same inputs always derive the same blinded_pk
+        assert_eq!(
+            blinded_pseudonym(&node_pk, &ephemeral_pk, &shared_secret),
+            blinded_pseudonym(&node_pk, &ephemeral_pk, &shared_secret)
+        );
+
+        // a fresh ephemeral key pair for the same node changes both the
+        // blinding_factor and blinded_pk, even though node_pk didn't change
+        let (other_ephemeral_pk, other_ephemeral_sk) = gen_keypair();
+        let other_shared_secret = encrypt_precompute(&node_pk, &other_ephemeral_sk);
+        assert_ne!(
+            blinded_pseudonym(&node_pk, &ephemeral_pk, &shared_secret),
+            blinded_pseudonym(&node_pk, &other_ephemeral_pk, &other_shared_secret)
+        );
+    }
+
+    #[test]
+    fn blinded_onion_path_can_be_walked_hop_by_hop() {
+        let nodes_with_keys = make_nodes(3);
+        let nodes: Vec<(IpPort, PublicKey)> = nodes_with_keys.iter()
+            .map(|&(ref ip_port, pk, _)| (ip_port.clone(), pk))
+            .collect();
+
+        let path = BlindedOnionPath::new(&nodes);
+
+        // the sender only ever sees the blinded ids and ephemeral keys -
+        // walk the path the way every hop would, using only its own real
+        // secret key and the hop addressed to it
+        for (i, hop) in path.hops.iter().enumerate() {
+            let node_sk = &nodes_with_keys[i].2;
+            let result = BlindedOnionPath::open_hop(hop, node_sk).unwrap();
+            if i + 1 < nodes.len() {
+                let (next_ip_port, next_ephemeral_pk) = result.unwrap();
+                assert_eq!(next_ip_port, nodes[i + 1].0);
+                assert_eq!(next_ephemeral_pk, path.hops[i + 1].ephemeral_pk);
+            } else {
+                assert!(result.is_none());
+            }
+        }
+    }
+
+    #[test]
+    fn blinded_onion_path_open_hop_fails_for_wrong_key() {
+        let nodes_with_keys = make_nodes(2);
+        let nodes: Vec<(IpPort, PublicKey)> = nodes_with_keys.iter()
+            .map(|&(ref ip_port, pk, _)| (ip_port.clone(), pk))
+            .collect();
+        let (_eve_pk, eve_sk) = gen_keypair();
+
+        let path = BlindedOnionPath::new(&nodes);
+
+        assert!(BlindedOnionPath::open_hop(&path.hops[0], &eve_sk).is_err());
+    }
+}
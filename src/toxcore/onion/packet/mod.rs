@@ -0,0 +1,37 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Onion packets.
+*/
+
+mod onion_request_0;
+mod onion_request_1;
+mod onion_request_2;
+mod onion_error;
+mod onion_message;
+mod blinded_onion_path;
+pub mod onion_padding;
+
+pub use self::onion_request_0::*;
+pub use self::onion_request_1::*;
+pub use self::onion_request_2::*;
+pub use self::onion_error::*;
+pub use self::onion_message::*;
+pub use self::blinded_onion_path::*;
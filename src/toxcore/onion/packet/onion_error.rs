@@ -0,0 +1,398 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! OnionError packet that lets a relay that can't forward an onion request
+tell the originator why. `EncryptedOnionError` is the sealed error itself;
+[`OnionErrorResponse`](./struct.OnionErrorResponse.html) pairs it with the
+`OnionReturn` the failing node received on the forward trip, so the response
+is routed back to the originator through the same chain of relays, each
+peeling its own `OnionReturn` layer exactly as it would for any other onion
+response.
+*/
+
+use super::*;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+
+use nom::be_u8;
+use std::io::{Error, ErrorKind};
+
+/// Reason a relay gives up forwarding an onion request.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OnionErrorCode {
+    /// The next node in the path couldn't be reached.
+    NextHopUnreachable,
+    /// The decrypted payload was rejected by this node.
+    PayloadRejected
+}
+
+impl OnionErrorCode {
+    fn to_byte(self) -> u8 {
+        match self {
+            OnionErrorCode::NextHopUnreachable => 0,
+            OnionErrorCode::PayloadRejected => 1
+        }
+    }
+
+    fn from_byte(byte: u8) -> Option<OnionErrorCode> {
+        match byte {
+            0 => Some(OnionErrorCode::NextHopUnreachable),
+            1 => Some(OnionErrorCode::PayloadRejected),
+            _ => None
+        }
+    }
+}
+
+/** Unencrypted error reported by a relay that failed to forward an onion
+request.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `OnionErrorCode`
+`1`      | `0` if no offending node follows, `1` otherwise
+`0` or `19` | `IpPort` of the offending node, if present
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionError {
+    /// What went wrong while forwarding the request
+    pub code: OnionErrorCode,
+    /// Address of the node that could not be reached, if relevant
+    pub node: Option<IpPort>
+}
+
+impl FromBytes for OnionError {
+    named!(from_bytes<OnionError>, do_parse!(
+        code: map_opt!(be_u8, OnionErrorCode::from_byte) >>
+        has_node: be_u8 >>
+        node: cond!(has_node != 0, call!(IpPort::from_bytes)) >>
+        eof!() >>
+        (OnionError { code, node })
+    ));
+}
+
+impl ToBytes for OnionError {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(self.code.to_byte()) >>
+            gen_be_u8!(if self.node.is_some() { 1 } else { 0 }) >>
+            gen_cond!(self.node.is_some(), gen_call!(|buf, ip_port| IpPort::to_bytes(ip_port, buf), self.node.as_ref().unwrap()))
+        )
+    }
+}
+
+/** `OnionError` sealed with the same per-hop `PrecomputedKey` that was used
+to decrypt the forward packet, so it can be sent back unchanged through the
+matching `OnionReturn` layer.
+
+Serialized form:
+
+Length | Content
+------ | ------
+`24`   | `Nonce`
+variable | Payload
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EncryptedOnionError {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Encrypted payload
+    pub payload: Vec<u8>
+}
+
+impl FromBytes for EncryptedOnionError {
+    named!(from_bytes<EncryptedOnionError>, do_parse!(
+        nonce: call!(Nonce::from_bytes) >>
+        payload: rest >>
+        (EncryptedOnionError { nonce, payload: payload.to_vec() })
+    ));
+}
+
+impl ToBytes for EncryptedOnionError {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.payload)
+        )
+    }
+}
+
+impl EncryptedOnionError {
+    /// Seal an `OnionError` with `shared_secret`.
+    pub fn new(shared_secret: &PrecomputedKey, error: &OnionError) -> EncryptedOnionError {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = error.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], &nonce, shared_secret);
+
+        EncryptedOnionError { nonce, payload }
+    }
+
+    /** Try to decrypt and parse this `OnionError` with `shared_secret`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionError`
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionError, Error> {
+        let decrypted = open_precomputed(&self.payload, &self.nonce, shared_secret)
+            .map_err(|e| {
+                debug!("Decrypting OnionError failed!");
+                Error::new(ErrorKind::Other,
+                    format!("OnionError decrypt error: {:?}", e))
+            })?;
+        match OnionError::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                error!(target: "Onion", "OnionError deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionError deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                error!(target: "Onion", "OnionError deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionError deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, error) => {
+                Ok(error)
+            }
+        }
+    }
+
+    /** Try `shared_secrets` in path order until one of them decrypts this
+    `EncryptedOnionError`, returning the index of the hop that produced it
+    together with the parsed `OnionError`.
+
+    The caller is expected to pass the `PrecomputedKey`s returned by
+    [`OnionPath::new`](../onion_path/struct.OnionPath.html#method.new)
+    for the path the failed request was sent over.
+    */
+    pub fn get_payload_with_any(&self, shared_secrets: &[PrecomputedKey]) -> Result<(usize, OnionError), Error> {
+        shared_secrets.iter()
+            .enumerate()
+            .filter_map(|(i, shared_secret)| self.get_payload(shared_secret).ok().map(|error| (i, error)))
+            .next()
+            .ok_or_else(|| Error::new(ErrorKind::Other, "OnionError decrypt error: no matching hop"))
+    }
+}
+
+/** `EncryptedOnionError` together with the `OnionReturn` it's routed home
+through - the node that gives up forwarding an onion request sends this back
+to the address `onion_return` was received from, and every relay on the way
+peels its own `OnionReturn` layer off just like it would for a normal onion
+response.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`59`/`118` | `OnionReturn`
+variable   | `EncryptedOnionError`
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionErrorResponse {
+    /// Return address this response is routed back through
+    pub onion_return: OnionReturn,
+    /// The actual encrypted error
+    pub payload: EncryptedOnionError
+}
+
+impl FromBytes for OnionErrorResponse {
+    named!(from_bytes<OnionErrorResponse>, do_parse!(
+        onion_return: call!(OnionReturn::from_bytes) >>
+        payload: call!(EncryptedOnionError::from_bytes) >>
+        (OnionErrorResponse { onion_return, payload })
+    ));
+}
+
+impl ToBytes for OnionErrorResponse {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return) >>
+            gen_call!(|buf, payload| EncryptedOnionError::to_bytes(payload, buf), &self.payload)
+        )
+    }
+}
+
+impl OnionErrorResponse {
+    /// Seal `error` with `shared_secret`, routed back through `onion_return`.
+    pub fn new(shared_secret: &PrecomputedKey, error: &OnionError, onion_return: OnionReturn) -> OnionErrorResponse {
+        OnionErrorResponse {
+            onion_return,
+            payload: EncryptedOnionError::new(shared_secret, error)
+        }
+    }
+
+    /** Try `shared_secrets` in path order until one of them decrypts
+    `self.payload`, see [`EncryptedOnionError::get_payload_with_any`](./struct.EncryptedOnionError.html#method.get_payload_with_any).
+    */
+    pub fn get_payload_with_any(&self, shared_secrets: &[PrecomputedKey]) -> Result<(usize, OnionError), Error> {
+        self.payload.get_payload_with_any(shared_secrets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        onion_error_without_node_encode_decode,
+        OnionError {
+            code: OnionErrorCode::PayloadRejected,
+            node: None
+        }
+    );
+
+    encode_decode_test!(
+        onion_error_with_node_encode_decode,
+        OnionError {
+            code: OnionErrorCode::NextHopUnreachable,
+            node: Some(IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            })
+        }
+    );
+
+    encode_decode_test!(
+        encrypted_onion_error_encode_decode,
+        EncryptedOnionError {
+            nonce: gen_nonce(),
+            payload: vec![42, 123]
+        }
+    );
+
+    #[test]
+    fn onion_error_encrypt_decrypt() {
+        let (_alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let error = OnionError {
+            code: OnionErrorCode::NextHopUnreachable,
+            node: Some(IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            })
+        };
+        let encrypted_error = EncryptedOnionError::new(&shared_secret, &error);
+        let decoded_error = encrypted_error.get_payload(&shared_secret).unwrap();
+        assert_eq!(decoded_error, error);
+    }
+
+    #[test]
+    fn onion_error_encrypt_decrypt_invalid_key() {
+        let (_alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let error = OnionError {
+            code: OnionErrorCode::PayloadRejected,
+            node: None
+        };
+        let encrypted_error = EncryptedOnionError::new(&shared_secret, &error);
+        let eve_shared_secret = encrypt_precompute(&bob_pk, &eve_sk);
+        let decoded_error = encrypted_error.get_payload(&eve_shared_secret);
+        assert!(decoded_error.is_err());
+    }
+
+    #[test]
+    fn onion_error_get_payload_with_any_finds_the_failing_hop() {
+        let (_pk0, sk0) = gen_keypair();
+        let (pk1, sk1) = gen_keypair();
+        let (pk2, sk2) = gen_keypair();
+        let (pk3, _sk3) = gen_keypair();
+        let shared_secrets = vec![
+            encrypt_precompute(&pk1, &sk0),
+            encrypt_precompute(&pk2, &sk1),
+            encrypt_precompute(&pk3, &sk2)
+        ];
+        let error = OnionError {
+            code: OnionErrorCode::NextHopUnreachable,
+            node: None
+        };
+        // the second hop is the one that actually sealed the error
+        let encrypted_error = EncryptedOnionError::new(&shared_secrets[1], &error);
+
+        let (hop, decoded_error) = encrypted_error.get_payload_with_any(&shared_secrets).unwrap();
+        assert_eq!(hop, 1);
+        assert_eq!(decoded_error, error);
+    }
+
+    #[test]
+    fn onion_error_get_payload_with_any_fails_if_no_key_matches() {
+        let (_pk0, sk0) = gen_keypair();
+        let (pk1, _sk1) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&pk1, &sk0);
+        let eve_shared_secret = encrypt_precompute(&pk1, &eve_sk);
+        let error = OnionError {
+            code: OnionErrorCode::PayloadRejected,
+            node: None
+        };
+        let encrypted_error = EncryptedOnionError::new(&shared_secret, &error);
+
+        assert!(encrypted_error.get_payload_with_any(&[eve_shared_secret]).is_err());
+    }
+
+    const ONION_RETURN_1_PAYLOAD_SIZE: usize = ONION_RETURN_1_SIZE - NONCEBYTES;
+
+    encode_decode_test!(
+        onion_error_response_encode_decode,
+        OnionErrorResponse {
+            onion_return: OnionReturn {
+                nonce: gen_nonce(),
+                payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+            },
+            payload: EncryptedOnionError {
+                nonce: gen_nonce(),
+                payload: vec![42, 123]
+            }
+        }
+    );
+
+    #[test]
+    fn onion_error_response_get_payload_with_any_finds_the_failing_hop() {
+        let (_pk0, sk0) = gen_keypair();
+        let (pk1, sk1) = gen_keypair();
+        let (pk2, _sk2) = gen_keypair();
+        let shared_secrets = vec![
+            encrypt_precompute(&pk1, &sk0),
+            encrypt_precompute(&pk2, &sk1)
+        ];
+        let error = OnionError {
+            code: OnionErrorCode::NextHopUnreachable,
+            node: None
+        };
+        let onion_return = OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let response = OnionErrorResponse::new(&shared_secrets[1], &error, onion_return);
+
+        let (hop, decoded_error) = response.get_payload_with_any(&shared_secrets).unwrap();
+        assert_eq!(hop, 1);
+        assert_eq!(decoded_error, error);
+    }
+}
@@ -0,0 +1,88 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! OnionMessagePayload that carries an arbitrary, application-defined blob
+through the onion path instead of a DHT announce or data request.
+*/
+
+use super::*;
+
+use toxcore::binary_io::*;
+
+use nom::{be_u8, rest};
+
+/** Payload of an onion message. It rides as the innermost `inner` bytes of
+an [`OnionRequest2Payload`](./struct.OnionRequest2Payload.html) the same way
+an onion announce or data request would, so it reuses the existing
+`OnionRequest0`/`OnionRequest1`/`OnionRequest2` transport without inventing a
+parallel packet format.
+
+`kind` is an application-defined tag used to dispatch the message to the
+right handler at the final hop - see
+[`OnionMessageHandlers`](../onion_message_handlers/struct.OnionMessageHandlers.html).
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | Message kind
+variable | Message
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionMessagePayload {
+    /// Application-defined tag used to pick a handler for `inner`
+    pub kind: u8,
+    /// Opaque, end-to-end encrypted application payload
+    pub inner: Vec<u8>
+}
+
+impl FromBytes for OnionMessagePayload {
+    named!(from_bytes<OnionMessagePayload>, do_parse!(
+        kind: be_u8 >>
+        inner: rest >>
+        (OnionMessagePayload {
+            kind,
+            inner: inner.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionMessagePayload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(self.kind) >>
+            gen_slice!(self.inner)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        onion_message_payload_encode_decode,
+        OnionMessagePayload {
+            kind: 7,
+            inner: vec![42, 123]
+        }
+    );
+}
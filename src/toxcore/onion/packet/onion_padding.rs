@@ -0,0 +1,151 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! Pad an onion payload's plaintext to a constant size before it's sealed,
+so that an observer watching a relay can't correlate packets by the length
+of their ciphertext. The real length is recorded as a trailing marker inside
+the padded plaintext itself - this never touches the wire format of whatever
+payload struct is being padded, so every layer keeps parsing the same bytes
+whether or not they were padded before being sealed.
+
+A single constant target size doesn't work once layers nest: the innermost
+layer's sealed, padded ciphertext becomes part of the *next* layer's own
+plaintext (plus that layer's `IpPort`/`PublicKey`), so every layer out is
+necessarily bigger than the one it wraps. [`pad`](fn.pad.html) and
+[`unpad`](fn.unpad.html) therefore take an explicit target size, and each
+layer of [`OnionRequest0`](../struct.OnionRequest0.html)/
+[`OnionRequest1`](../struct.OnionRequest1.html)/
+[`OnionRequest2`](../struct.OnionRequest2.html) gets its own constant,
+[`ONION_REQUEST_0_PADDED_SIZE`](constant.ONION_REQUEST_0_PADDED_SIZE.html)
+down to
+[`ONION_REQUEST_2_PADDED_SIZE`](constant.ONION_REQUEST_2_PADDED_SIZE.html),
+each one `LAYER_STEP` bigger than the layer it wraps.
+*/
+
+use toxcore::crypto_core::*;
+
+use std::io::{Error, ErrorKind};
+
+/// Size in bytes of a fixed-size `IpPort` as it appears inside an onion
+/// payload (see e.g. `OnionRequest0Payload`).
+const IP_PORT_SIZE: usize = 19;
+
+/// Bytes `seal_precomputed` adds on top of whatever plaintext it seals.
+const MAC_SIZE: usize = MACBYTES;
+
+/** Bytes of overhead one more layer of onion wrapping adds on top of a
+sealed, padded payload: the `IpPort`/`PublicKey` the wrapping layer's own
+payload carries alongside `inner`, the `seal_precomputed` MAC already baked
+into `inner`, and the 2 byte length marker [`pad`](fn.pad.html) needs room
+for at every layer.
+*/
+const LAYER_STEP: usize = IP_PORT_SIZE + PUBLICKEYBYTES + MAC_SIZE + 2;
+
+/// Constant size an `OnionRequest2Payload` plaintext is padded to before
+/// being sealed - the innermost, smallest layer.
+pub const ONION_REQUEST_2_PADDED_SIZE: usize = 1024;
+
+/// Constant size an `OnionRequest1Payload` plaintext is padded to before
+/// being sealed. Once it wraps a sealed, padded `OnionRequest2` its
+/// plaintext is always exactly this size, one [`LAYER_STEP`](constant.LAYER_STEP.html)
+/// bigger than [`ONION_REQUEST_2_PADDED_SIZE`](constant.ONION_REQUEST_2_PADDED_SIZE.html).
+pub const ONION_REQUEST_1_PADDED_SIZE: usize = ONION_REQUEST_2_PADDED_SIZE + LAYER_STEP;
+
+/// Constant size an `OnionRequest0Payload` plaintext is padded to before
+/// being sealed - the outermost layer, one more
+/// [`LAYER_STEP`](constant.LAYER_STEP.html) out than
+/// [`ONION_REQUEST_1_PADDED_SIZE`](constant.ONION_REQUEST_1_PADDED_SIZE.html).
+pub const ONION_REQUEST_0_PADDED_SIZE: usize = ONION_REQUEST_1_PADDED_SIZE + LAYER_STEP;
+
+/** Pad `plain` up to `padded_size` bytes with pseudo-random filler followed
+by a 2 byte big-endian marker recording `plain`'s real length, so that
+[`unpad`](fn.unpad.html) can recover it after the round trip.
+*/
+pub fn pad(plain: &[u8], padded_size: usize) -> Vec<u8> {
+    assert!(plain.len() + 2 <= padded_size, "onion payload is too big to pad to {}", padded_size);
+
+    let mut padded = vec![0; padded_size];
+    padded[..plain.len()].copy_from_slice(plain);
+    randombytes_into(&mut padded[plain.len()..padded_size - 2]);
+
+    let real_len = plain.len() as u16;
+    padded[padded_size - 2] = (real_len >> 8) as u8;
+    padded[padded_size - 1] = real_len as u8;
+
+    padded
+}
+
+/** Strip the filler [`pad`](fn.pad.html) added, given the same `padded_size`
+`pad` was called with. Unlike inferring padding from `plain`'s length, the
+caller always knows up front whether a payload was padded - this should only
+ever be called on a payload that was actually sealed with
+[`pad`](fn.pad.html), never speculatively.
+
+Returns `Error` if `plain` isn't exactly `padded_size` bytes long, or if its
+trailing length marker doesn't describe a valid prefix of it.
+*/
+pub fn unpad(mut plain: Vec<u8>, padded_size: usize) -> Result<Vec<u8>, Error> {
+    if plain.len() != padded_size {
+        return Err(Error::new(ErrorKind::Other,
+            format!("padded onion payload is {} bytes, expected {}", plain.len(), padded_size)));
+    }
+
+    let real_len = ((plain[padded_size - 2] as usize) << 8)
+        | plain[padded_size - 1] as usize;
+    if real_len > padded_size - 2 {
+        return Err(Error::new(ErrorKind::Other, "padded onion payload has an invalid length marker"));
+    }
+
+    plain.truncate(real_len);
+    Ok(plain)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pad_unpad() {
+        let plain = vec![42, 123];
+        let padded = pad(&plain, ONION_REQUEST_2_PADDED_SIZE);
+        assert_eq!(padded.len(), ONION_REQUEST_2_PADDED_SIZE);
+        assert_eq!(unpad(padded, ONION_REQUEST_2_PADDED_SIZE).unwrap(), plain);
+    }
+
+    #[test]
+    fn pad_produces_constant_length_regardless_of_input_size() {
+        assert_eq!(
+            pad(&[42; 8], ONION_REQUEST_2_PADDED_SIZE).len(),
+            pad(&[42; 400], ONION_REQUEST_2_PADDED_SIZE).len()
+        );
+    }
+
+    #[test]
+    fn unpad_fails_on_unpadded_input() {
+        let plain = vec![42; 8];
+        assert!(unpad(plain, ONION_REQUEST_2_PADDED_SIZE).is_err());
+    }
+
+    #[test]
+    fn layer_sizes_grow_outward() {
+        assert!(ONION_REQUEST_1_PADDED_SIZE > ONION_REQUEST_2_PADDED_SIZE);
+        assert!(ONION_REQUEST_0_PADDED_SIZE > ONION_REQUEST_1_PADDED_SIZE);
+    }
+}
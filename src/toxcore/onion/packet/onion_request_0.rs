@@ -0,0 +1,370 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! OnionRequest0 packet with OnionRequest0Payload
+*/
+
+use super::*;
+use super::onion_padding;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+
+use nom::rest;
+use std::io::{Error, ErrorKind};
+
+/** First onion request packet. It's sent from a client to the first node from
+onion chain. Payload should be encrypted with temporary generated `SecretKey`
+and with DHT `PublicKey` of the first node.
+
+Unlike [`OnionRequest1`](./struct.OnionRequest1.html) and
+[`OnionRequest2`](./struct.OnionRequest2.html) it doesn't carry an
+`OnionReturn` since the first node can reply directly to the address this
+packet was sent from.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x80`
+`24`     | `Nonce`
+`32`     | Temporary `PublicKey`
+variable | Payload
+
+where payload is encrypted [`OnionRequest0Payload`](./struct.OnionRequest0Payload.html)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest0 {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary `PublicKey` for the current encrypted payload
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>
+}
+
+impl FromBytes for OnionRequest0 {
+    named!(from_bytes<OnionRequest0>, do_parse!(
+        verify!(rest_len, |len| len <= ONION_MAX_PACKET_SIZE) >>
+        tag!(&[0x80][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        payload: rest >>
+        (OnionRequest0 {
+            nonce,
+            temporary_pk,
+            payload: payload.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest0 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x80) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload)
+        )
+    }
+}
+
+impl OnionRequest0 {
+    /// Create new `OnionRequest0` object.
+    pub fn new(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, payload: OnionRequest0Payload) -> OnionRequest0 {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], &nonce, shared_secret);
+
+        OnionRequest0 { nonce, temporary_pk: *temporary_pk, payload }
+    }
+
+    /** Create new `OnionRequest0` object, padding `payload`'s plaintext to
+    [`ONION_REQUEST_0_PADDED_SIZE`](../onion_padding/constant.ONION_REQUEST_0_PADDED_SIZE.html)
+    with [`onion_padding::pad`](../onion_padding/fn.pad.html) before sealing
+    it, so that the length of the resulting ciphertext doesn't depend on the
+    real size of `payload`.
+
+    Use [`get_padded_payload`](#method.get_padded_payload), not
+    [`get_payload`](#method.get_payload), to read it back.
+    */
+    pub fn new_padded(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, payload: OnionRequest0Payload) -> OnionRequest0 {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let padded = onion_padding::pad(&buf[..size], onion_padding::ONION_REQUEST_0_PADDED_SIZE);
+        let payload = seal_precomputed(&padded, &nonce, shared_secret);
+
+        OnionRequest0 { nonce, temporary_pk: *temporary_pk, payload }
+    }
+
+    /** Decrypt payload and try to parse it as `OnionRequest0Payload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionRequest0Payload`
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionRequest0Payload, Error> {
+        let decrypted = self.decrypt(shared_secret)?;
+        OnionRequest0::parse_payload(decrypted)
+    }
+
+    /** Decrypt a payload sealed with [`new_padded`](#method.new_padded),
+    strip its [`onion_padding`](../onion_padding/index.html) and try to parse
+    it as `OnionRequest0Payload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - isn't padded to `ONION_REQUEST_0_PADDED_SIZE`
+    - fails to parse as `OnionRequest0Payload`
+    */
+    pub fn get_padded_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionRequest0Payload, Error> {
+        let decrypted = self.decrypt(shared_secret)?;
+        let decrypted = onion_padding::unpad(decrypted, onion_padding::ONION_REQUEST_0_PADDED_SIZE)
+            .map_err(|e| {
+                error!(target: "Onion", "OnionRequest0Payload padding error: {:?}", e);
+                e
+            })?;
+        OnionRequest0::parse_payload(decrypted)
+    }
+
+    fn decrypt(&self, shared_secret: &PrecomputedKey) -> Result<Vec<u8>, Error> {
+        open_precomputed(&self.payload, &self.nonce, shared_secret)
+            .map_err(|e| {
+                debug!("Decrypting OnionRequest0 failed!");
+                Error::new(ErrorKind::Other,
+                    format!("OnionRequest0 decrypt error: {:?}", e))
+            })
+    }
+
+    fn parse_payload(decrypted: Vec<u8>) -> Result<OnionRequest0Payload, Error> {
+        match OnionRequest0Payload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                error!(target: "Onion", "OnionRequest0Payload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionRequest0Payload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                error!(target: "Onion", "OnionRequest0Payload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionRequest0Payload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, inner) => {
+                Ok(inner)
+            }
+        }
+    }
+}
+
+/** Unencrypted payload of `OnionRequest0` packet.
+
+Inner payload should be sent to the next node with address from `ip_port` field.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`19`     | `IpPort` of the next node
+`32`     | Temporary `PublicKey`
+variable | Payload
+
+where payload is encrypted [`OnionRequest1Payload`](./struct.OnionRequest1Payload.html)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest0Payload {
+    /// Address of the next node in the onion path
+    pub ip_port: IpPort,
+    /// Temporary `PublicKey` for the current encrypted payload
+    pub temporary_pk: PublicKey,
+    /// Inner onion payload
+    pub inner: Vec<u8>
+}
+
+impl FromBytes for OnionRequest0Payload {
+    named!(from_bytes<OnionRequest0Payload>, do_parse!(
+        ip_port: call!(IpPort::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        inner: rest >>
+        (OnionRequest0Payload {
+            ip_port,
+            temporary_pk,
+            inner: inner.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest0Payload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_call!(|buf, ip_port| IpPort::to_bytes(ip_port, buf), &self.ip_port) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.inner)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    encode_decode_test!(
+        onion_request_0_encode_decode,
+        OnionRequest0 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42, 123]
+        }
+    );
+
+    encode_decode_test!(
+        onion_request_0_payload_encode_decode,
+        OnionRequest0Payload {
+            ip_port: IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42, 123]
+        }
+    );
+
+    #[test]
+    fn onion_request_0_payload_encrypt_decrypt() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42, 123]
+        };
+        // encode payload with shared secret
+        let onion_packet = OnionRequest0::new(&shared_secret, &alice_pk, payload.clone());
+        // decode payload with bob's secret key
+        let decoded_payload = onion_packet.get_payload(&shared_secret).unwrap();
+        // payloads should be equal
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn onion_request_0_payload_encrypt_decrypt_invalid_key() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42, 123]
+        };
+        // encode payload with shared secret
+        let onion_packet = OnionRequest0::new(&shared_secret, &alice_pk, payload.clone());
+        // try to decode payload with eve's secret key
+        let eve_shared_secret = encrypt_precompute(&bob_pk, &eve_sk);
+        let decoded_payload = onion_packet.get_payload(&eve_shared_secret);
+        assert!(decoded_payload.is_err());
+    }
+
+    #[test]
+    fn onion_request_0_decrypt_invalid() {
+        let symmetric_key = new_symmetric_key();
+        let nonce = gen_nonce();
+        let temporary_pk = gen_keypair().0;
+        // Try long invalid array
+        let invalid_payload = [42; 123];
+        let invalid_payload_encoded = seal_precomputed(&invalid_payload, &nonce, &symmetric_key);
+        let invalid_onion_request_0 = OnionRequest0 {
+            nonce,
+            temporary_pk,
+            payload: invalid_payload_encoded
+        };
+        assert!(invalid_onion_request_0.get_payload(&symmetric_key).is_err());
+        // Try short incomplete array
+        let invalid_payload = [];
+        let invalid_payload_encoded = seal_precomputed(&invalid_payload, &nonce, &symmetric_key);
+        let invalid_onion_request_0 = OnionRequest0 {
+            nonce,
+            temporary_pk,
+            payload: invalid_payload_encoded
+        };
+        assert!(invalid_onion_request_0.get_payload(&symmetric_key).is_err());
+    }
+
+    #[test]
+    fn onion_request_0_padded_payload_encrypt_decrypt() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest0Payload {
+            ip_port: IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42, 123]
+        };
+        let onion_packet = OnionRequest0::new_padded(&shared_secret, &alice_pk, payload.clone());
+        let decoded_payload = onion_packet.get_padded_payload(&shared_secret).unwrap();
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn onion_request_0_padded_packets_have_constant_length() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let ip_port = IpPort {
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+
+        let short_payload = OnionRequest0Payload {
+            ip_port: ip_port.clone(),
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 8]
+        };
+        let long_payload = OnionRequest0Payload {
+            ip_port,
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 400]
+        };
+
+        let short_packet = OnionRequest0::new_padded(&shared_secret, &alice_pk, short_payload);
+        let long_packet = OnionRequest0::new_padded(&shared_secret, &alice_pk, long_payload);
+
+        let mut short_buf = [0; ONION_MAX_PACKET_SIZE];
+        let mut long_buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, short_size) = short_packet.to_bytes((&mut short_buf, 0)).unwrap();
+        let (_, long_size) = long_packet.to_bytes((&mut long_buf, 0)).unwrap();
+
+        assert_eq!(short_size, long_size);
+    }
+}
@@ -22,6 +22,7 @@
 */
 
 use super::*;
+use super::onion_padding;
 
 use toxcore::binary_io::*;
 use toxcore::crypto_core::*;
@@ -102,6 +103,25 @@ impl OnionRequest1 {
         OnionRequest1 { nonce, temporary_pk: *temporary_pk, payload, onion_return }
     }
 
+    /** Create new `OnionRequest1` object, padding `payload`'s plaintext to
+    [`ONION_REQUEST_1_PADDED_SIZE`](../onion_padding/constant.ONION_REQUEST_1_PADDED_SIZE.html)
+    with [`onion_padding::pad`](../onion_padding/fn.pad.html) before sealing
+    it, so that the length of the resulting ciphertext doesn't depend on the
+    real size of `payload`.
+
+    Use [`get_padded_payload`](#method.get_padded_payload), not
+    [`get_payload`](#method.get_payload), to read it back.
+    */
+    pub fn new_padded(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, payload: OnionRequest1Payload, onion_return: OnionReturn) -> OnionRequest1 {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let padded = onion_padding::pad(&buf[..size], onion_padding::ONION_REQUEST_1_PADDED_SIZE);
+        let payload = seal_precomputed(&padded, &nonce, shared_secret);
+
+        OnionRequest1 { nonce, temporary_pk: *temporary_pk, payload, onion_return }
+    }
+
     /** Decrypt payload and try to parse it as `OnionRequest1Payload`.
 
     Returns `Error` in case of failure:
@@ -110,12 +130,40 @@ impl OnionRequest1 {
     - fails to parse as `OnionRequest1Payload`
     */
     pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionRequest1Payload, Error> {
-        let decrypted = open_precomputed(&self.payload, &self.nonce, shared_secret)
+        let decrypted = self.decrypt(shared_secret)?;
+        OnionRequest1::parse_payload(decrypted)
+    }
+
+    /** Decrypt a payload sealed with [`new_padded`](#method.new_padded),
+    strip its [`onion_padding`](../onion_padding/index.html) and try to parse
+    it as `OnionRequest1Payload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - isn't padded to `ONION_REQUEST_1_PADDED_SIZE`
+    - fails to parse as `OnionRequest1Payload`
+    */
+    pub fn get_padded_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionRequest1Payload, Error> {
+        let decrypted = self.decrypt(shared_secret)?;
+        let decrypted = onion_padding::unpad(decrypted, onion_padding::ONION_REQUEST_1_PADDED_SIZE)
+            .map_err(|e| {
+                error!(target: "Onion", "OnionRequest1Payload padding error: {:?}", e);
+                e
+            })?;
+        OnionRequest1::parse_payload(decrypted)
+    }
+
+    fn decrypt(&self, shared_secret: &PrecomputedKey) -> Result<Vec<u8>, Error> {
+        open_precomputed(&self.payload, &self.nonce, shared_secret)
             .map_err(|e| {
                 debug!("Decrypting OnionRequest1 failed!");
                 Error::new(ErrorKind::Other,
                     format!("OnionRequest1 decrypt error: {:?}", e))
-            })?;
+            })
+    }
+
+    fn parse_payload(decrypted: Vec<u8>) -> Result<OnionRequest1Payload, Error> {
         match OnionRequest1Payload::from_bytes(&decrypted) {
             IResult::Incomplete(e) => {
                 error!(target: "Onion", "OnionRequest1Payload deserialize error: {:?}", e);
@@ -296,4 +344,62 @@ mod tests {
         };
         assert!(invalid_onion_request_1.get_payload(&symmetric_key).is_err());
     }
+
+    #[test]
+    fn onion_request_1_padded_payload_encrypt_decrypt() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest1Payload {
+            ip_port: IpPort {
+                ip_addr: "5.6.7.8".parse().unwrap(),
+                port: 12345
+            },
+            temporary_pk: gen_keypair().0,
+            inner: vec![42, 123]
+        };
+        let onion_return = OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+        let onion_packet = OnionRequest1::new_padded(&shared_secret, &alice_pk, payload.clone(), onion_return);
+        let decoded_payload = onion_packet.get_padded_payload(&shared_secret).unwrap();
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn onion_request_1_padded_packets_have_constant_length() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let ip_port = IpPort {
+            ip_addr: "5.6.7.8".parse().unwrap(),
+            port: 12345
+        };
+
+        let short_payload = OnionRequest1Payload {
+            ip_port: ip_port.clone(),
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 8]
+        };
+        let long_payload = OnionRequest1Payload {
+            ip_port,
+            temporary_pk: gen_keypair().0,
+            inner: vec![42; 400]
+        };
+        let onion_return = || OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_1_PAYLOAD_SIZE]
+        };
+
+        let short_packet = OnionRequest1::new_padded(&shared_secret, &alice_pk, short_payload, onion_return());
+        let long_packet = OnionRequest1::new_padded(&shared_secret, &alice_pk, long_payload, onion_return());
+
+        let mut short_buf = [0; ONION_MAX_PACKET_SIZE];
+        let mut long_buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, short_size) = short_packet.to_bytes((&mut short_buf, 0)).unwrap();
+        let (_, long_size) = long_packet.to_bytes((&mut long_buf, 0)).unwrap();
+
+        assert_eq!(short_size, long_size);
+    }
 }
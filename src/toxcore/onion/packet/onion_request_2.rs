@@ -0,0 +1,347 @@
+/*
+    Copyright (C) 2013 Tox project All Rights Reserved.
+    Copyright © 2018 Evgeny Kurnevsky <kurnevsky@gmail.com>
+
+    This file is part of Tox.
+
+    Tox is libre software: you can redistribute it and/or modify
+    it under the terms of the GNU General Public License as published by
+    the Free Software Foundation, either version 3 of the License, or
+    (at your option) any later version.
+
+    Tox is distributed in the hope that it will be useful,
+    but WITHOUT ANY WARRANTY; without even the implied warranty of
+    MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+    GNU General Public License for more details.
+
+    You should have received a copy of the GNU General Public License
+    along with Tox.  If not, see <http://www.gnu.org/licenses/>.
+*/
+
+/*! OnionRequest2 packet with OnionRequest2Payload
+*/
+
+use super::*;
+use super::onion_padding;
+
+use toxcore::binary_io::*;
+use toxcore::crypto_core::*;
+
+use nom::rest;
+use std::io::{Error, ErrorKind};
+
+/** Third and last onion request packet. It's sent from the second to the
+third (last) node from onion chain. Payload should be encrypted with
+temporary generated `SecretKey` and with DHT `PublicKey` of receiver.
+
+Unlike [`OnionRequest0`](./struct.OnionRequest0.html) the last node doesn't
+forward the payload any further by address - the decrypted payload is handled
+locally, e.g. as an onion announce or onion data request.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+`1`      | `0x82`
+`24`     | `Nonce`
+`32`     | Temporary `PublicKey`
+variable | Payload
+`118`    | `OnionReturn`
+
+where payload is encrypted [`OnionRequest2Payload`](./struct.OnionRequest2Payload.html)
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest2 {
+    /// Nonce for the current encrypted payload
+    pub nonce: Nonce,
+    /// Temporary `PublicKey` for the current encrypted payload
+    pub temporary_pk: PublicKey,
+    /// Encrypted payload
+    pub payload: Vec<u8>,
+    /// Return address encrypted by the second node from onion chain
+    pub onion_return: OnionReturn
+}
+
+impl FromBytes for OnionRequest2 {
+    named!(from_bytes<OnionRequest2>, do_parse!(
+        verify!(rest_len, |len| len <= ONION_MAX_PACKET_SIZE) >>
+        tag!(&[0x82][..]) >>
+        nonce: call!(Nonce::from_bytes) >>
+        temporary_pk: call!(PublicKey::from_bytes) >>
+        rest_len: rest_len >>
+        payload: cond_reduce!(
+            rest_len >= ONION_RETURN_2_SIZE,
+            take!(rest_len - ONION_RETURN_2_SIZE)
+        ) >>
+        onion_return: call!(OnionReturn::from_bytes) >>
+        (OnionRequest2 {
+            nonce,
+            temporary_pk,
+            payload: payload.to_vec(),
+            onion_return
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest2 {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_be_u8!(0x82) >>
+            gen_slice!(self.nonce.as_ref()) >>
+            gen_slice!(self.temporary_pk.as_ref()) >>
+            gen_slice!(self.payload) >>
+            gen_call!(|buf, onion_return| OnionReturn::to_bytes(onion_return, buf), &self.onion_return)
+        )
+    }
+}
+
+impl OnionRequest2 {
+    /// Create new `OnionRequest2` object.
+    pub fn new(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, payload: OnionRequest2Payload, onion_return: OnionReturn) -> OnionRequest2 {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let payload = seal_precomputed(&buf[..size], &nonce, shared_secret);
+
+        OnionRequest2 { nonce, temporary_pk: *temporary_pk, payload, onion_return }
+    }
+
+    /** Create new `OnionRequest2` object, padding `payload`'s plaintext to
+    [`ONION_REQUEST_2_PADDED_SIZE`](../onion_padding/constant.ONION_REQUEST_2_PADDED_SIZE.html)
+    with [`onion_padding::pad`](../onion_padding/fn.pad.html) before sealing
+    it, so that the length of the resulting ciphertext doesn't depend on the
+    real size of `payload`.
+
+    Use [`get_padded_payload`](#method.get_padded_payload), not
+    [`get_payload`](#method.get_payload), to read it back.
+    */
+    pub fn new_padded(shared_secret: &PrecomputedKey, temporary_pk: &PublicKey, payload: OnionRequest2Payload, onion_return: OnionReturn) -> OnionRequest2 {
+        let nonce = gen_nonce();
+        let mut buf = [0; ONION_MAX_PACKET_SIZE];
+        let (_, size) = payload.to_bytes((&mut buf, 0)).unwrap();
+        let padded = onion_padding::pad(&buf[..size], onion_padding::ONION_REQUEST_2_PADDED_SIZE);
+        let payload = seal_precomputed(&padded, &nonce, shared_secret);
+
+        OnionRequest2 { nonce, temporary_pk: *temporary_pk, payload, onion_return }
+    }
+
+    /** Decrypt payload and try to parse it as `OnionRequest2Payload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - fails to parse as `OnionRequest2Payload`
+    */
+    pub fn get_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionRequest2Payload, Error> {
+        let decrypted = self.decrypt(shared_secret)?;
+        OnionRequest2::parse_payload(decrypted)
+    }
+
+    /** Decrypt a payload sealed with [`new_padded`](#method.new_padded),
+    strip its [`onion_padding`](../onion_padding/index.html) and try to parse
+    it as `OnionRequest2Payload`.
+
+    Returns `Error` in case of failure:
+
+    - fails to decrypt
+    - isn't padded to `ONION_REQUEST_2_PADDED_SIZE`
+    - fails to parse as `OnionRequest2Payload`
+    */
+    pub fn get_padded_payload(&self, shared_secret: &PrecomputedKey) -> Result<OnionRequest2Payload, Error> {
+        let decrypted = self.decrypt(shared_secret)?;
+        let decrypted = onion_padding::unpad(decrypted, onion_padding::ONION_REQUEST_2_PADDED_SIZE)
+            .map_err(|e| {
+                error!(target: "Onion", "OnionRequest2Payload padding error: {:?}", e);
+                e
+            })?;
+        OnionRequest2::parse_payload(decrypted)
+    }
+
+    fn decrypt(&self, shared_secret: &PrecomputedKey) -> Result<Vec<u8>, Error> {
+        open_precomputed(&self.payload, &self.nonce, shared_secret)
+            .map_err(|e| {
+                debug!("Decrypting OnionRequest2 failed!");
+                Error::new(ErrorKind::Other,
+                    format!("OnionRequest2 decrypt error: {:?}", e))
+            })
+    }
+
+    fn parse_payload(decrypted: Vec<u8>) -> Result<OnionRequest2Payload, Error> {
+        match OnionRequest2Payload::from_bytes(&decrypted) {
+            IResult::Incomplete(e) => {
+                error!(target: "Onion", "OnionRequest2Payload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionRequest2Payload deserialize error: {:?}", e)))
+            },
+            IResult::Error(e) => {
+                error!(target: "Onion", "OnionRequest2Payload deserialize error: {:?}", e);
+                Err(Error::new(ErrorKind::Other,
+                    format!("OnionRequest2Payload deserialize error: {:?}", e)))
+            },
+            IResult::Done(_, inner) => {
+                Ok(inner)
+            }
+        }
+    }
+}
+
+/** Unencrypted payload of `OnionRequest2` packet.
+
+Unlike the payloads of `OnionRequest0` and `OnionRequest1` this one carries no
+further routing information - it is handled by the node that decrypts it,
+e.g. parsed as an onion announce or onion data request.
+
+Serialized form:
+
+Length   | Content
+-------- | ------
+variable | Payload
+
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OnionRequest2Payload {
+    /// Inner onion payload
+    pub inner: Vec<u8>
+}
+
+impl FromBytes for OnionRequest2Payload {
+    named!(from_bytes<OnionRequest2Payload>, do_parse!(
+        inner: rest >>
+        (OnionRequest2Payload {
+            inner: inner.to_vec()
+        })
+    ));
+}
+
+impl ToBytes for OnionRequest2Payload {
+    fn to_bytes<'a>(&self, buf: (&'a mut [u8], usize)) -> Result<(&'a mut [u8], usize), GenError> {
+        do_gen!(buf,
+            gen_slice!(self.inner)
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ONION_RETURN_2_PAYLOAD_SIZE: usize = ONION_RETURN_2_SIZE - NONCEBYTES;
+
+    encode_decode_test!(
+        onion_request_2_encode_decode,
+        OnionRequest2 {
+            nonce: gen_nonce(),
+            temporary_pk: gen_keypair().0,
+            payload: vec![42, 123],
+            onion_return: OnionReturn {
+                nonce: gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
+        }
+    );
+
+    encode_decode_test!(
+        onion_request_2_payload_encode_decode,
+        OnionRequest2Payload {
+            inner: vec![42, 123]
+        }
+    );
+
+    #[test]
+    fn onion_request_2_payload_encrypt_decrypt() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest2Payload {
+            inner: vec![42, 123]
+        };
+        let onion_return = OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        // encode payload with shared secret
+        let onion_packet = OnionRequest2::new(&shared_secret, &alice_pk, payload.clone(), onion_return);
+        // decode payload with bob's secret key
+        let decoded_payload = onion_packet.get_payload(&shared_secret).unwrap();
+        // payloads should be equal
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn onion_request_2_payload_encrypt_decrypt_invalid_key() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let (_eve_pk, eve_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest2Payload {
+            inner: vec![42, 123]
+        };
+        let onion_return = OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        // encode payload with shared secret
+        let onion_packet = OnionRequest2::new(&shared_secret, &alice_pk, payload.clone(), onion_return);
+        // try to decode payload with eve's secret key
+        let eve_shared_secret = encrypt_precompute(&bob_pk, &eve_sk);
+        let decoded_payload = onion_packet.get_payload(&eve_shared_secret);
+        assert!(decoded_payload.is_err());
+    }
+
+    #[test]
+    fn onion_request_2_padded_payload_encrypt_decrypt() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let payload = OnionRequest2Payload {
+            inner: vec![42, 123]
+        };
+        let onion_return = OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        // encode payload with shared secret
+        let onion_packet = OnionRequest2::new_padded(&shared_secret, &alice_pk, payload.clone(), onion_return);
+        // decode payload with bob's secret key
+        let decoded_payload = onion_packet.get_padded_payload(&shared_secret).unwrap();
+        // payloads should be equal
+        assert_eq!(decoded_payload, payload);
+    }
+
+    #[test]
+    fn onion_request_2_padded_packets_have_constant_length() {
+        let (alice_pk, alice_sk) = gen_keypair();
+        let (bob_pk, _bob_sk) = gen_keypair();
+        let shared_secret = encrypt_precompute(&bob_pk, &alice_sk);
+        let short_payload = OnionRequest2Payload { inner: vec![42, 123] };
+        let long_payload = OnionRequest2Payload { inner: vec![42; 400] };
+        let onion_return = OnionReturn {
+            nonce: gen_nonce(),
+            payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+        };
+        let short_packet = OnionRequest2::new_padded(&shared_secret, &alice_pk, short_payload, onion_return.clone());
+        let long_packet = OnionRequest2::new_padded(&shared_secret, &alice_pk, long_payload, onion_return);
+        assert_eq!(short_packet.payload.len(), long_packet.payload.len());
+    }
+
+    #[test]
+    fn onion_request_2_decrypt_invalid() {
+        let symmetric_key = new_symmetric_key();
+        let nonce = gen_nonce();
+        let temporary_pk = gen_keypair().0;
+        // Try long invalid array
+        let invalid_payload = [42; 123];
+        let invalid_payload_encoded = seal_precomputed(&invalid_payload, &nonce, &symmetric_key);
+        let invalid_onion_request_2 = OnionRequest2 {
+            nonce,
+            temporary_pk,
+            payload: invalid_payload_encoded,
+            onion_return: OnionReturn {
+                nonce: gen_nonce(),
+                payload: vec![42; ONION_RETURN_2_PAYLOAD_SIZE]
+            }
+        };
+        assert!(invalid_onion_request_2.get_payload(&symmetric_key).is_err());
+    }
+}